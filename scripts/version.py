@@ -0,0 +1,19 @@
+# Adds the git commit to the build, so the firmware can report what it was built from
+import subprocess
+
+Import("env")
+
+
+def git(*args):
+    try:
+        return subprocess.check_output(["git", *args], stderr=subprocess.DEVNULL).decode().strip()
+    except Exception:
+        return "unknown"
+
+
+env.Append(
+    CPPDEFINES=[
+        ("GIT_COMMIT_HASH", env.StringifyMacro(git("rev-parse", "HEAD"))),
+        ("GIT_DESCRIBE", env.StringifyMacro(git("describe", "--always", "--dirty"))),
+    ]
+)
@@ -5,6 +5,14 @@
 #include <WiFi.h>
 #include <DNSServer.h>
 #include <WebServer.h>
+#include <ESPmDNS.h>
+#include <WebSocketsServer.h>
+#include <Preferences.h>
+#include <Update.h>
+#include <esp_ota_ops.h>
+#include <mbedtls/sha256.h>
+#include <mbedtls/base64.h>
+#include <Adafruit_SSD1306.h>
 // put function declarations here:
 Adafruit_VEML7700 veml = Adafruit_VEML7700();
 extern const uint8_t index_html_start[] asm("_binary_src_html_index_html_start");
@@ -12,13 +20,164 @@ extern const uint8_t index_html_end[] asm("_binary_src_html_index_html_end");
 
 DNSServer dnsServer;
 WebServer server(80);
+// pushes every new reading to the page, so it doesn't have to reload
+WebSocketsServer webSocket(81);
+Preferences stats;
+Preferences wifi_prefs;
+Preferences auth_prefs;
+Preferences sensor_prefs;
+Adafruit_SSD1306 display(128, 64, &Wire, -1);
 
 float baseline_reading;
 IPAddress apIP(192, 168, 0, 1);
 IPAddress netMsk(255, 255, 255, 0);
 float final_td;
+float current_lux;
+uint16_t current_als;
+const int CALIBRATION_ATTEMPTS = 3;
 
-void replaceTemplateWithData(uint8_t *html, size_t htmlSize, const char *templateTag, float replacement)
+// default for the i2c timeout, can be overridden with -DI2C_TIMEOUT_MS=... in platformio.ini
+// and changed at runtime through /config/measurement, which stores it in the "sensor" preferences
+#ifndef I2C_TIMEOUT_MS
+#define I2C_TIMEOUT_MS 50
+#endif
+static_assert(I2C_TIMEOUT_MS >= 10 && I2C_TIMEOUT_MS <= 1000, "I2C_TIMEOUT_MS must be between 10 and 1000");
+
+// minimum time between two sensor reads, page requests in between get the last value
+#ifndef MIN_SAMPLE_INTERVAL_MS
+#define MIN_SAMPLE_INTERVAL_MS 200
+#endif
+unsigned long last_sample = 0;
+
+// after this many failed reads in a row the sensor gets re-initialized, after the second one the esp reboots
+#ifndef SENSOR_REINIT_THRESHOLD
+#define SENSOR_REINIT_THRESHOLD 5
+#endif
+#ifndef SENSOR_REBOOT_THRESHOLD
+#define SENSOR_REBOOT_THRESHOLD 20
+#endif
+static_assert(SENSOR_REINIT_THRESHOLD < SENSOR_REBOOT_THRESHOLD, "SENSOR_REINIT_THRESHOLD must be lower than SENSOR_REBOOT_THRESHOLD");
+int sensor_failures = 0;
+
+// the total is only written to flash every few minutes to spare it
+const unsigned long STATS_SAVE_INTERVAL_MS = 5 * 60 * 1000;
+unsigned long measurements_since_boot = 0;
+unsigned long measurements_total = 0;
+unsigned long saved_measurements_total = 0;
+unsigned long last_stats_save = 0;
+
+// an optional ssd1306 oled on the sensor bus, it stays dormant if nothing answers on this address
+#ifndef OLED_ADDRESS
+#define OLED_ADDRESS 0x3C
+#endif
+const unsigned long DISPLAY_UPDATE_INTERVAL_MS = 1000;
+bool display_found = false;
+// "auto" probes for it on boot, "on" skips the probe, "off" never touches it. saved in the "sensor" preferences
+String oled_mode = "auto";
+unsigned long last_display_update = 0;
+
+// set by scripts/version.py
+#ifndef GIT_COMMIT_HASH
+#define GIT_COMMIT_HASH "unknown"
+#endif
+#ifndef GIT_DESCRIBE
+#define GIT_DESCRIBE "unknown"
+#endif
+const char *BUILD_TIMESTAMP = __DATE__ " " __TIME__;
+
+// a td above this means nothing (or nothing visible) is in the sensor
+#ifndef NO_FILAMENT_THRESHOLD
+#define NO_FILAMENT_THRESHOLD 95.0
+#endif
+
+// sensor settings ordered from most to least sensitive, the first one that doesn't saturate is used
+struct SensorConfig
+{
+  uint8_t gain;
+  uint8_t integration_time;
+};
+const SensorConfig SENSOR_CONFIGS[] = {
+    {VEML7700_GAIN_1, VEML7700_IT_100MS},
+    {VEML7700_GAIN_1_4, VEML7700_IT_100MS},
+    {VEML7700_GAIN_1_8, VEML7700_IT_100MS},
+    {VEML7700_GAIN_1_8, VEML7700_IT_50MS},
+    {VEML7700_GAIN_1_8, VEML7700_IT_25MS},
+};
+const uint16_t ALS_SATURATION = 0xF000;
+SensorConfig sensor_config = {VEML7700_GAIN_1_8, VEML7700_IT_100MS};
+SensorConfig baseline_config = sensor_config;
+
+struct Baseline
+{
+  float lux;
+  SensorConfig config;
+};
+
+// adjustable at runtime through /config/measurement, the interval is reset on reboot, the timeout is saved
+const uint16_t MIN_I2C_TIMEOUT_MS = 10;
+const uint16_t MAX_I2C_TIMEOUT_MS = 1000;
+unsigned long min_sample_interval = MIN_SAMPLE_INTERVAL_MS;
+uint16_t i2c_timeout = I2C_TIMEOUT_MS;
+const int BASELINE_SAMPLE_COUNT = 10;
+
+// keep the last few log lines around so they can be read over http
+const int LOG_LINES = 32;
+const int LOG_LINE_LENGTH = 96;
+char log_buffer[LOG_LINES][LOG_LINE_LENGTH];
+int log_next = 0;
+int log_count = 0;
+
+void logLine(const char *format, ...)
+{
+  char *line = log_buffer[log_next];
+  va_list args;
+  va_start(args, format);
+  vsnprintf(line, LOG_LINE_LENGTH, format, args);
+  va_end(args);
+  Serial.println(line);
+
+  log_next = (log_next + 1) % LOG_LINES;
+  if (log_count < LOG_LINES)
+  {
+    log_count++;
+  }
+}
+
+// json routes can be used from dashboards on other origins
+void sendJson(int code, const String &json)
+{
+  server.sendHeader("Access-Control-Allow-Origin", "*");
+  server.send(code, "application/json", json);
+}
+
+// for user controlled values like the ssid or the request path
+String jsonEscape(const String &value)
+{
+  String escaped;
+  for (size_t i = 0; i < value.length(); i++)
+  {
+    char c = value.charAt(i);
+    if (c == '"' || c == '\\')
+    {
+      escaped += '\\';
+      escaped += c;
+    }
+    else if (static_cast<uint8_t>(c) < 0x20)
+    {
+      char unicode[7];
+      snprintf(unicode, sizeof(unicode), "\\u%04x", c);
+      escaped += unicode;
+    }
+    else
+    {
+      escaped += c;
+    }
+  }
+  return escaped;
+}
+
+// the page is patched in place, so the replacement must not be longer than the tag
+void replaceTemplateWithText(uint8_t *html, size_t htmlSize, const char *templateTag, const char *replacement)
 {
   // Convert the binary data to a String
   String htmlString;
@@ -31,12 +190,7 @@ void replaceTemplateWithData(uint8_t *html, size_t htmlSize, const char *templat
   int pos = htmlString.indexOf(templateTag);
   if (pos != -1)
   {
-    // Convert the float to a string with 2 decimal places
-    char floatStr[10];                    // Adjust the size as needed
-    dtostrf(replacement, 4, 2, floatStr); // 4 is the minimum width, 2 is the number of digits after the decimal point
-
-    // Replace the template with the formatted float string
-    htmlString.replace(templateTag, floatStr);
+    htmlString.replace(templateTag, replacement);
 
     // Update the non-const array with the modified String
     size_t newHtmlSize = htmlString.length();
@@ -55,6 +209,16 @@ void replaceTemplateWithData(uint8_t *html, size_t htmlSize, const char *templat
   }
 }
 
+void replaceTemplateWithData(uint8_t *html, size_t htmlSize, const char *templateTag, float replacement, int decimals = 2)
+{
+  // Convert the float to a string with 2 decimal places by default
+  char floatStr[10];                           // Adjust the size as needed
+  dtostrf(replacement, 4, decimals, floatStr); // 4 is the minimum width, decimals is the number of digits after the decimal point
+
+  // Replace the template with the formatted float string
+  replaceTemplateWithText(html, htmlSize, templateTag, floatStr);
+}
+
 void handleRoot()
 {
   size_t htmlSize = 0;
@@ -69,64 +233,1021 @@ void handleRoot()
   const char *templateTag = "{{value}}";
   float data = final_td;
   replaceTemplateWithData(html, htmlSize, templateTag, data);
+  replaceTemplateWithData(html, htmlSize, "{{raw_lux}}", current_lux);
+  replaceTemplateWithData(html, htmlSize, "{{baseline}}", baseline_reading);
+  replaceTemplateWithData(html, htmlSize, "{{raw_als}}", current_als, 0);
+  replaceTemplateWithText(html, htmlSize, "{{sensor_error_hidden}}", sensor_failures > 0 ? "" : "hidden");
   server.send(200, "text/html", reinterpret_cast<const char *>(html));
   delete[] html;
 }
 
+void handleLog()
+{
+  String log;
+  int first = (log_next - log_count + LOG_LINES) % LOG_LINES;
+  for (int i = 0; i < log_count; i++)
+  {
+    log += log_buffer[(first + i) % LOG_LINES];
+    log += '\n';
+  }
+  server.send(200, "text/plain", log);
+}
+
+// also called before every planned reboot, so the count since the last periodic save isn't lost
+void saveStats()
+{
+  if (measurements_total != saved_measurements_total)
+  {
+    stats.putULong("total", measurements_total);
+    saved_measurements_total = measurements_total;
+  }
+  last_stats_save = millis();
+}
+
+// defaults to Td-Free-XXXX (end of the mac) so several devices can be told apart
+String hotspotSsid()
+{
+  uint8_t mac[6];
+  WiFi.softAPmacAddress(mac);
+  char default_ssid[16];
+  snprintf(default_ssid, sizeof(default_ssid), "Td-Free-%02X%02X", mac[4], mac[5]);
+  return wifi_prefs.getString("ap_ssid", default_ssid);
+}
+
+void startMdns()
+{
+  if (!MDNS.begin("tdfree"))
+  {
+    logLine("Starting mDNS failed");
+    return;
+  }
+  MDNS.addService("http", "tcp", 80);
+  logLine("Reachable at http://tdfree.local");
+}
+
+void startHotspot()
+{
+  WiFi.mode(WIFI_AP);
+  WiFi.softAPConfig(apIP, apIP, netMsk);
+  String ssid = hotspotSsid();
+  String password = wifi_prefs.getString("ap_pw", "");
+  // an empty password gives an open hotspot
+  WiFi.softAP(ssid.c_str(), password.c_str());
+  logLine("Hotspot: %s (%s)", ssid.c_str(), password.length() > 0 ? "WPA2" : "open");
+  dnsServer.start(53, "*", apIP);
+  startMdns();
+}
+
+void handleRestartWifi()
+{
+  server.send(200, "text/plain", "restarting wifi");
+  delay(50);
+  logLine("Restarting wifi");
+  MDNS.end();
+  dnsServer.stop();
+  WiFi.softAPdisconnect(true);
+  WiFi.mode(WIFI_OFF);
+  delay(100);
+  startHotspot();
+}
+
+void handleSetHotspot()
+{
+  String ssid = server.arg("ssid");
+  String password = server.arg("password");
+  if (ssid.length() < 1 || ssid.length() > 32)
+  {
+    sendJson(400, "{\"error\":\"ssid must be between 1 and 32 characters\"}");
+    return;
+  }
+  if (password.length() != 0 && (password.length() < 8 || password.length() > 63))
+  {
+    sendJson(400, "{\"error\":\"password must be empty or between 8 and 63 characters\"}");
+    return;
+  }
+
+  wifi_prefs.putString("ap_ssid", ssid);
+  wifi_prefs.putString("ap_pw", password);
+  handleRestartWifi();
+}
+
+void handleForgetWifi()
+{
+  wifi_prefs.remove("ap_ssid");
+  wifi_prefs.remove("ap_pw");
+  logLine("Hotspot settings cleared, rebooting");
+  server.send(200, "text/plain", "hotspot settings cleared, rebooting");
+  delay(50);
+  saveStats();
+  ESP.restart();
+}
+
+void sendMeasurementConfig()
+{
+  String json = "{";
+  json += "\"min_sample_interval_ms\":" + String(min_sample_interval);
+  json += ",\"i2c_timeout_ms\":" + String(i2c_timeout);
+  json += ",\"baseline_samples\":" + String(BASELINE_SAMPLE_COUNT);
+  json += ",\"calibration_attempts\":" + String(CALIBRATION_ATTEMPTS);
+  json += "}";
+  sendJson(200, json);
+}
+
+// leaves value untouched if the arg is missing, returns false if it is malformed or out of range
+bool parseBoundedArg(const char *name, long min, long max, long &value)
+{
+  if (!server.hasArg(name))
+  {
+    return true;
+  }
+  String arg = server.arg(name);
+  char *end;
+  long parsed = strtol(arg.c_str(), &end, 10);
+  if (arg.length() == 0 || *end != 0 || parsed < min || parsed > max)
+  {
+    return false;
+  }
+  value = parsed;
+  return true;
+}
+
+void handleSetMeasurementConfig()
+{
+  // validate everything first so a bad field doesn't leave a half-applied config
+  long interval = min_sample_interval;
+  long timeout = i2c_timeout;
+  if (!parseBoundedArg("min_sample_interval_ms", 0, 60000, interval))
+  {
+    sendJson(400, "{\"error\":\"min_sample_interval_ms must be between 0 and 60000\"}");
+    return;
+  }
+  if (!parseBoundedArg("i2c_timeout_ms", MIN_I2C_TIMEOUT_MS, MAX_I2C_TIMEOUT_MS, timeout))
+  {
+    sendJson(400, "{\"error\":\"i2c_timeout_ms must be between 10 and 1000\"}");
+    return;
+  }
+
+  min_sample_interval = interval;
+  if (timeout != i2c_timeout)
+  {
+    sensor_prefs.putUShort("i2c_timeout", timeout);
+  }
+  i2c_timeout = timeout;
+  Wire.setTimeOut(i2c_timeout);
+  logLine("Measurement config: interval %lu ms, i2c timeout %d ms", min_sample_interval, i2c_timeout);
+  sendMeasurementConfig();
+}
+
+void initDisplay();
+
+void sendDisplayConfig()
+{
+  String json = "{";
+  json += "\"oled\":\"" + oled_mode + "\"";
+  json += ",\"oled_found\":" + String(display_found ? "true" : "false");
+  json += "}";
+  sendJson(200, json);
+}
+
+void handleSetDisplayConfig()
+{
+  String mode = server.arg("oled");
+  if (mode != "auto" && mode != "on" && mode != "off")
+  {
+    sendJson(400, "{\"error\":\"oled must be auto, on or off\"}");
+    return;
+  }
+  if (mode != oled_mode)
+  {
+    sensor_prefs.putString("oled", mode);
+  }
+  oled_mode = mode;
+  if (oled_mode == "off")
+  {
+    if (display_found)
+    {
+      display.clearDisplay();
+      display.display();
+    }
+    display_found = false;
+  }
+  else if (!display_found)
+  {
+    initDisplay();
+  }
+  logLine("OLED setting: %s", oled_mode.c_str());
+  sendDisplayConfig();
+}
+
+void handleStats()
+{
+  String json = "{";
+  json += "\"measurements_since_boot\":" + String(measurements_since_boot);
+  json += ",\"measurements_total\":" + String(measurements_total);
+  json += ",\"uptime_ms\":" + String(millis());
+  json += "}";
+  sendJson(200, json);
+}
+
+void countMeasurement()
+{
+  measurements_since_boot++;
+  measurements_total++;
+  if (millis() - last_stats_save >= STATS_SAVE_INTERVAL_MS)
+  {
+    saveStats();
+  }
+}
+
+void handleI2cBench()
+{
+  const int reads = 100;
+  int errors = 0;
+  unsigned long start = micros();
+  for (int i = 0; i < reads; i++)
+  {
+    Wire.beginTransmission(VEML7700_I2CADDR_DEFAULT);
+    Wire.write(VEML7700_ALS_DATA);
+    if (Wire.endTransmission(false) != 0 || Wire.requestFrom(VEML7700_I2CADDR_DEFAULT, 2) != 2)
+    {
+      errors++;
+      continue;
+    }
+    Wire.read();
+    Wire.read();
+  }
+  unsigned long duration = micros() - start;
+
+  String json = "{";
+  json += "\"reads\":" + String(reads);
+  json += ",\"errors\":" + String(errors);
+  json += ",\"duration_us\":" + String(duration);
+  json += ",\"reads_per_second\":" + String(reads * 1000000.0 / duration, 1);
+  json += "}";
+  logLine("I2C bench: %d reads, %d errors in %lu us", reads, errors, duration);
+  sendJson(200, json);
+}
+
+void handleSensorConfig()
+{
+  String json = "{";
+  json += "\"enabled\":" + String(veml.enabled() ? "true" : "false");
+  json += ",\"gain\":" + String(veml.getGainValue(), 3);
+  json += ",\"integration_time_ms\":" + String(veml.getIntegrationTimeValue());
+  json += ",\"power_save\":" + String(veml.powerSaveEnabled() ? "true" : "false");
+  json += ",\"persistence\":" + String(veml.getPersistence());
+  json += "}";
+  sendJson(200, json);
+}
+
+void handleVersion()
+{
+  String json = "{";
+  json += "\"version\":\"" GIT_DESCRIBE "\"";
+  json += ",\"commit\":\"" GIT_COMMIT_HASH "\"";
+  json += ",\"build_timestamp\":\"" + String(BUILD_TIMESTAMP) + "\"";
+  json += ",\"sdk\":\"" + String(ESP.getSdkVersion()) + "\"";
+  json += "}";
+  sendJson(200, json);
+}
+
+void sendMetric(const char *name, const char *type, const char *help, const String &value)
+{
+  server.sendContent(String("# HELP ") + name + " " + help + "\n");
+  server.sendContent(String("# TYPE ") + name + " " + type + "\n");
+  server.sendContent(String(name) + " " + value + "\n");
+}
+
+// prometheus text format, sent line by line to keep the memory use low
+void handleMetrics()
+{
+  server.setContentLength(CONTENT_LENGTH_UNKNOWN);
+  server.send(200, "text/plain; version=0.0.4", "");
+  sendMetric("tdfree_td_value", "gauge", "Current Td value", String(final_td, 2));
+  sendMetric("tdfree_lux", "gauge", "Current lux reading", String(current_lux, 2));
+  sendMetric("tdfree_raw_als", "gauge", "Current raw ALS count", String(current_als));
+  sendMetric("tdfree_baseline_lux", "gauge", "Lux reading without filament taken at boot", String(baseline_reading, 2));
+  sendMetric("tdfree_sensor_failures", "gauge", "Sensor reads failed in a row", String(sensor_failures));
+  sendMetric("tdfree_measurements_total", "counter", "Measurements taken since the first boot", String(measurements_total));
+  sendMetric("tdfree_wifi_clients", "gauge", "Clients connected to the hotspot", String(WiFi.softAPgetStationNum()));
+  sendMetric("tdfree_free_heap_bytes", "gauge", "Free heap", String(ESP.getFreeHeap()));
+  sendMetric("tdfree_uptime_seconds", "counter", "Seconds since boot", String(millis() / 1000));
+  server.sendContent("");
+}
+
+void handleMeasure()
+{
+  String json = "{";
+  json += "\"td\":" + String(final_td, 2);
+  json += ",\"raw_lux\":" + String(current_lux, 2);
+  json += ",\"raw_als\":" + String(current_als);
+  json += ",\"baseline\":" + String(baseline_reading, 2);
+  bool sensor_ok = sensor_failures == 0;
+  // without a baseline td is always 0, which must not look like a filament
+  bool calibrated = baseline_reading != 0;
+  json += ",\"filament_present\":" + String(sensor_ok && calibrated && final_td < NO_FILAMENT_THRESHOLD ? "true" : "false");
+  json += ",\"sensor_ok\":" + String(sensor_ok ? "true" : "false");
+  json += ",\"calibrated\":" + String(calibrated ? "true" : "false");
+  json += "}";
+  sendJson(200, json);
+}
+
+void handleStatus()
+{
+  String json = "{";
+  json += "\"mode\":\"hotspot\"";
+  json += ",\"ssid\":\"" + jsonEscape(WiFi.softAPSSID()) + "\"";
+  json += ",\"ip\":\"" + WiFi.softAPIP().toString() + "\"";
+  json += ",\"clients\":" + String(WiFi.softAPgetStationNum());
+  json += ",\"uptime_ms\":" + String(millis());
+  json += "}";
+  sendJson(200, json);
+}
+
+bool sensorResponds();
+
+void handlePipeline()
+{
+  // served from the loop's latest sample instead of taking one here, so this never blocks the loop
+  if (last_sample == 0 || sensor_failures > 0)
+  {
+    sendJson(503, "{\"error\":\"no sensor reading available\"}");
+    return;
+  }
+  uint16_t raw_als = current_als;
+  float lux = current_lux;
+  float ratio = baseline_reading != 0 ? lux / baseline_reading : 0;
+
+  String json = "{";
+  json += "\"raw_als\":" + String(raw_als);
+  json += ",\"gain\":" + String(veml.getGainValue(), 3);
+  json += ",\"integration_time_ms\":" + String(veml.getIntegrationTimeValue());
+  json += ",\"raw_lux\":" + String(lux, 2);
+  json += ",\"baseline\":" + String(baseline_reading, 2);
+  json += ",\"ratio\":" + String(ratio, 4);
+  json += ",\"td\":" + String(ratio * 100.0, 2);
+  json += "}";
+  sendJson(200, json);
+}
+
+void handleApi();
+
+// set when a firmware upload starts, so an error left over from an earlier upload isn't reported for a request without a body
+bool update_started = false;
+
+// receives the firmware as a multipart upload and writes it to the other ota partition
+void handleUpdateUpload()
+{
+  HTTPUpload &upload = server.upload();
+  switch (upload.status)
+  {
+  case UPLOAD_FILE_START:
+    logLine("Update: receiving %s", upload.filename.c_str());
+    update_started = true;
+    if (!Update.begin(UPDATE_SIZE_UNKNOWN))
+    {
+      logLine("Update: %s", Update.errorString());
+    }
+    break;
+  case UPLOAD_FILE_WRITE:
+    // the first chunk is checked for a valid image header by Update.write
+    if (Update.isRunning() && Update.write(upload.buf, upload.currentSize) != upload.currentSize)
+    {
+      logLine("Update: %s", Update.errorString());
+    }
+    break;
+  case UPLOAD_FILE_END:
+    if (Update.isRunning() && Update.end(true))
+    {
+      logLine("Update: %u bytes written", upload.totalSize);
+    }
+    else
+    {
+      logLine("Update: %s", Update.errorString());
+    }
+    break;
+  default:
+    logLine("Update: upload aborted");
+    Update.abort();
+    break;
+  }
+}
+
+// receives the firmware as a plain request body, e.g. curl --data-binary
+void handleUpdateRaw()
+{
+  HTTPRaw &raw = server.raw();
+  switch (raw.status)
+  {
+  case RAW_START:
+    logLine("Update: receiving raw body");
+    update_started = true;
+    if (!Update.begin(UPDATE_SIZE_UNKNOWN))
+    {
+      logLine("Update: %s", Update.errorString());
+    }
+    break;
+  case RAW_WRITE:
+    if (Update.isRunning() && Update.write(raw.buf, raw.currentSize) != raw.currentSize)
+    {
+      logLine("Update: %s", Update.errorString());
+    }
+    break;
+  case RAW_END:
+    if (Update.isRunning() && Update.end(true))
+    {
+      logLine("Update: %u bytes written", raw.totalSize);
+    }
+    else
+    {
+      logLine("Update: %s", Update.errorString());
+    }
+    break;
+  default:
+    logLine("Update: upload aborted");
+    Update.abort();
+    break;
+  }
+}
+
+// WebServer calls the same handler for multipart uploads and raw bodies
+void handleUpdateBody()
+{
+  if (server.header("Content-Type").startsWith("multipart/"))
+  {
+    handleUpdateUpload();
+  }
+  else
+  {
+    handleUpdateRaw();
+  }
+}
+
+void handleUpdateDone()
+{
+  bool started = update_started;
+  update_started = false;
+  if (!started)
+  {
+    sendJson(400, "{\"error\":\"no firmware received\"}");
+    return;
+  }
+  if (Update.hasError())
+  {
+    sendJson(500, "{\"error\":\"" + String(Update.errorString()) + "\"}");
+    return;
+  }
+  if (!Update.isFinished())
+  {
+    sendJson(400, "{\"error\":\"firmware upload incomplete\"}");
+    return;
+  }
+  sendJson(200, "{\"status\":\"updated, rebooting\"}");
+  delay(100);
+  saveStats();
+  ESP.restart();
+}
+
+// keeps the arduino core from marking a new ota image valid before setup() ran, see markFirmwareValid()
+extern "C" bool verifyRollbackLater()
+{
+  return true;
+}
+
+// a freshly updated image that crashes before this point is rolled back on the next boot
+void markFirmwareValid()
+{
+  const esp_partition_t *running = esp_ota_get_running_partition();
+  esp_ota_img_states_t state;
+  if (esp_ota_get_state_partition(running, &state) == ESP_OK && state == ESP_OTA_IMG_PENDING_VERIFY)
+  {
+    esp_ota_mark_app_valid_cancel_rollback();
+    logLine("Update: new firmware marked as valid");
+  }
+}
+
+String hashPassword(const String &password)
+{
+  uint8_t digest[32];
+  mbedtls_sha256(reinterpret_cast<const unsigned char *>(password.c_str()), password.length(), digest, 0);
+  char hex[65];
+  for (int i = 0; i < 32; i++)
+  {
+    snprintf(hex + i * 2, 3, "%02x", digest[i]);
+  }
+  return String(hex);
+}
+
+// looks at every character, so the time it takes doesn't tell how much of the hash was right
+bool hashesMatch(const String &a, const String &b)
+{
+  if (a.length() != b.length())
+  {
+    return false;
+  }
+  volatile uint8_t diff = 0;
+  for (size_t i = 0; i < a.length(); i++)
+  {
+    diff |= a[i] ^ b[i];
+  }
+  return diff == 0;
+}
+
+// only the password of the basic auth header is checked, the user name can be anything
+bool isAuthorized()
+{
+  String stored_hash = auth_prefs.getString("pw_hash", "");
+  if (stored_hash.length() == 0)
+  {
+    return true;
+  }
+  String header = server.header("Authorization");
+  if (!header.startsWith("Basic "))
+  {
+    return false;
+  }
+  String encoded = header.substring(6);
+  unsigned char decoded[128];
+  size_t decoded_length = 0;
+  if (mbedtls_base64_decode(decoded, sizeof(decoded) - 1, &decoded_length, reinterpret_cast<const unsigned char *>(encoded.c_str()), encoded.length()) != 0)
+  {
+    return false;
+  }
+  decoded[decoded_length] = 0;
+  String credentials = reinterpret_cast<const char *>(decoded);
+  int separator = credentials.indexOf(':');
+  if (separator == -1)
+  {
+    return false;
+  }
+  return hashesMatch(hashPassword(credentials.substring(separator + 1)), stored_hash);
+}
+
+// decided once when an upload starts, so the password isn't hashed again for every chunk
+bool upload_authorized = false;
+
+bool uploadStarting()
+{
+  if (server.header("Content-Type").startsWith("multipart/"))
+  {
+    return server.upload().status == UPLOAD_FILE_START;
+  }
+  return server.raw().status == RAW_START;
+}
+
+void handleSetAdminPassword()
+{
+  String password = server.arg("password");
+  if (password.length() == 0)
+  {
+    auth_prefs.remove("pw_hash");
+    logLine("Admin password removed");
+    sendJson(200, "{\"protected\":false}");
+    return;
+  }
+  if (password.length() < 8)
+  {
+    sendJson(400, "{\"error\":\"password must be at least 8 characters\"}");
+    return;
+  }
+  auth_prefs.putString("pw_hash", hashPassword(password));
+  logLine("Admin password set");
+  sendJson(200, "{\"protected\":true}");
+}
+
+// clears the admin password, the saved sensor settings and, with wifi=true, the hotspot settings. the measurement count is kept
+void handleFactoryReset()
+{
+  auth_prefs.clear();
+  sensor_prefs.clear();
+  if (server.arg("wifi") == "true")
+  {
+    wifi_prefs.clear();
+  }
+  logLine("Factory reset, rebooting");
+  sendJson(200, "{\"status\":\"reset, rebooting\"}");
+  delay(50);
+  saveStats();
+  ESP.restart();
+}
+
+void handleReboot()
+{
+  logLine("Rebooting");
+  sendJson(200, "{\"status\":\"rebooting\"}");
+  delay(50);
+  saveStats();
+  ESP.restart();
+}
+
+struct Route
+{
+  const char *path;
+  HTTPMethod method;
+  void (*handler)();
+  void (*upload)();
+};
+
+// every route gets registered from here, so /api always lists what is actually served
+const Route routes[] = {
+    {"/", HTTP_ANY, handleRoot},
+    {"/api", HTTP_GET, handleApi},
+    {"/debug/log", HTTP_ANY, handleLog},
+    {"/restart-wifi-only", HTTP_POST, handleRestartWifi},
+    {"/hotspot", HTTP_POST, handleSetHotspot},
+    {"/wifi/forget", HTTP_POST, handleForgetWifi},
+    {"/config/measurement", HTTP_GET, sendMeasurementConfig},
+    {"/config/measurement", HTTP_POST, handleSetMeasurementConfig},
+    {"/config/display", HTTP_GET, sendDisplayConfig},
+    {"/config/display", HTTP_POST, handleSetDisplayConfig},
+    {"/stats", HTTP_GET, handleStats},
+    {"/status", HTTP_GET, handleStatus},
+    {"/measure", HTTP_GET, handleMeasure},
+    {"/version", HTTP_GET, handleVersion},
+    {"/metrics", HTTP_GET, handleMetrics},
+    {"/update", HTTP_POST, handleUpdateDone, handleUpdateBody},
+    {"/admin-password", HTTP_POST, handleSetAdminPassword},
+    {"/factory_reset", HTTP_POST, handleFactoryReset},
+    {"/reboot", HTTP_POST, handleReboot},
+    {"/debug/i2c_bench", HTTP_POST, handleI2cBench},
+    {"/debug/sensor", HTTP_GET, handleSensorConfig},
+    {"/debug/pipeline", HTTP_GET, handlePipeline},
+};
+
+const char *methodName(HTTPMethod method)
+{
+  switch (method)
+  {
+  case HTTP_GET:
+    return "GET";
+  case HTTP_POST:
+    return "POST";
+  default:
+    return "ANY";
+  }
+}
+
+void handleApi()
+{
+  String json = "{\"routes\":[";
+  for (size_t i = 0; i < sizeof(routes) / sizeof(routes[0]); i++)
+  {
+    if (i > 0)
+    {
+      json += ",";
+    }
+    json += "{\"path\":\"" + String(routes[i].path) + "\",\"method\":\"" + methodName(routes[i].method) + "\"}";
+  }
+  json += "],\"features\":{";
+  json += "\"td\":true";
+  json += ",\"color\":false";
+  json += ",\"oled\":" + String(display_found ? "true" : "false");
+  json += ",\"oled_mode\":\"" + oled_mode + "\"";
+  json += ",\"websocket_port\":81";
+  json += "}}";
+  sendJson(200, json);
+}
+
+void broadcastReading()
+{
+  if (webSocket.connectedClients() == 0)
+  {
+    return;
+  }
+  String json = "{";
+  json += "\"td\":" + String(final_td, 2);
+  json += ",\"raw_lux\":" + String(current_lux, 2);
+  json += ",\"raw_als\":" + String(current_als);
+  json += ",\"sensor_ok\":" + String(sensor_failures == 0 ? "true" : "false");
+  json += "}";
+  webSocket.broadcastTXT(json);
+}
+
+bool wantsJson()
+{
+  return server.uri().startsWith("/api/") || server.header("Accept").indexOf("application/json") != -1;
+}
+
 void handleNotFound()
 {
+  // cors preflight for the json routes
+  if (server.method() == HTTP_OPTIONS)
+  {
+    server.sendHeader("Access-Control-Allow-Origin", "*");
+    server.sendHeader("Access-Control-Allow-Methods", "GET, POST, OPTIONS");
+    server.sendHeader("Access-Control-Allow-Headers", "Content-Type, Authorization");
+    server.send(204);
+    return;
+  }
+  // api clients get a proper 404, everything else is sent to the captive portal
+  if (wantsJson())
+  {
+    sendJson(404, "{\"error\":\"not found\",\"path\":\"" + jsonEscape(server.uri()) + "\"}");
+    return;
+  }
   server.sendHeader("Location", "/");
   server.send(302, "text/plain", "redirect to captive portal");
 }
 
+void applySensorConfig()
+{
+  veml.setGain(sensor_config.gain);
+  veml.setIntegrationTime(sensor_config.integration_time);
+}
+
+void autoGain()
+{
+  SensorConfig previous_config = sensor_config;
+  for (const SensorConfig &config : SENSOR_CONFIGS) {
+    sensor_config = config;
+    applySensorConfig();
+    // a failed read comes back as 0xFFFF, which would look like saturation
+    uint16_t raw_als = sensorResponds() ? veml.readALS(true) : 0xFFFF;
+    if (raw_als == 0xFFFF) {
+      logLine("Sensor read failed, keeping the previous gain");
+      sensor_config = previous_config;
+      applySensorConfig();
+      return;
+    }
+    if (raw_als < ALS_SATURATION) {
+      break;
+    }
+    logLine("Raw ALS %u is saturated, lowering sensitivity", raw_als);
+  }
+  logLine("Sensor gain: %.3f, integration time: %d ms", veml.getGainValue(), veml.getIntegrationTimeValue());
+}
+
+// a failed i2c read makes readALS return 0xFFFF (and readLux a huge value), so those make the whole attempt invalid
+Baseline takeBaselineReading()
+{
+  float max_reading = 0;
+  for (int i = 0; i < BASELINE_SAMPLE_COUNT; i++) {
+    if (!sensorResponds()) {
+      logLine("Sensor did not respond during calibration");
+      return {NAN, sensor_config};
+    }
+    max_reading += veml.readLux();
+    if (veml.readALS(false) == 0xFFFF) {
+      logLine("Sensor read failed during calibration");
+      return {NAN, sensor_config};
+    }
+    delay(200);
+  }
+  return {max_reading / BASELINE_SAMPLE_COUNT, sensor_config};
+}
+
+bool isValidBaseline(float reading)
+{
+  return !isnan(reading) && reading > 0;
+}
+
+// same formula Adafruit_VEML7700 uses for VEML_LUX_NORMAL, computeLux itself is private
+float alsToLux(uint16_t raw_als)
+{
+  return 0.0036 * (800.0 / veml.getIntegrationTimeValue()) * (2.0 / veml.getGainValue()) * raw_als;
+}
+
+bool sensorResponds()
+{
+  Wire.beginTransmission(VEML7700_I2CADDR_DEFAULT);
+  return Wire.endTransmission() == 0;
+}
+
+// returns true if the sensor is usable for this loop iteration
+// counts a failed ping or read, re-initializes and eventually reboots when it keeps failing
+void sensorFailed(const char *reason)
+{
+  sensor_failures++;
+  logLine("%s (%d in a row)", reason, sensor_failures);
+  if (sensor_failures >= SENSOR_REBOOT_THRESHOLD)
+  {
+    logLine("Sensor still not responding, rebooting");
+    delay(50);
+    saveStats();
+    ESP.restart();
+  }
+  else if (sensor_failures == SENSOR_REINIT_THRESHOLD)
+  {
+    logLine("Re-initializing sensor");
+    if (veml.begin())
+    {
+      Wire.setTimeOut(i2c_timeout);
+      // keep using the settings the baseline was taken with
+      sensor_config = baseline_config;
+      applySensorConfig();
+    }
+  }
+}
+
+bool checkSensor()
+{
+  if (sensorResponds())
+  {
+    return true;
+  }
+  sensorFailed("Sensor did not respond");
+  return false;
+}
+
+void initDisplay()
+{
+  if (oled_mode == "off")
+  {
+    logLine("OLED disabled");
+    return;
+  }
+  // with "on" it is set up even if the probe gets no answer, e.g. for a display that is slow to power up
+  if (oled_mode == "auto")
+  {
+    Wire.beginTransmission(OLED_ADDRESS);
+    if (Wire.endTransmission() != 0)
+    {
+      return;
+    }
+  }
+  if (!display.begin(SSD1306_SWITCHCAPVCC, OLED_ADDRESS, true, false))
+  {
+    return;
+  }
+  display_found = true;
+  display.setTextColor(SSD1306_WHITE);
+  logLine("OLED found at 0x%02X", OLED_ADDRESS);
+}
+
+void updateDisplay()
+{
+  if (!display_found || millis() - last_display_update < DISPLAY_UPDATE_INTERVAL_MS)
+  {
+    return;
+  }
+  last_display_update = millis();
+
+  display.clearDisplay();
+  display.setCursor(0, 0);
+  display.setTextSize(1);
+  display.println("Td-Free");
+  display.setTextSize(2);
+  display.println(final_td, 2);
+  display.setTextSize(1);
+  // the value above is the last good reading then
+  display.println(sensor_failures > 0 ? "Sensor error" : "");
+  display.print("WiFi: ");
+  display.println(WiFi.softAPSSID());
+  display.print("IP: ");
+  display.println(WiFi.softAPIP());
+  display.display();
+}
+
 void setup(void)
 {
   Serial.begin(9600);
-  Serial.println("Boot ok!");
+  logLine("Boot ok!");
+  logLine("Version %s (%s), built %s", GIT_DESCRIBE, GIT_COMMIT_HASH, BUILD_TIMESTAMP);
 
-  WiFi.mode(WIFI_AP);
-  WiFi.softAPConfig(apIP, apIP, netMsk);
-  WiFi.softAP("Td-Free");
-  dnsServer.start(53, "*", apIP);
+  stats.begin("stats", false);
+  measurements_total = stats.getULong("total", 0);
+  saved_measurements_total = measurements_total;
+  logLine("Measurements so far: %lu", measurements_total);
 
-  // serve a simple root page
-  server.on("/", handleRoot);
+  wifi_prefs.begin("wifi", false);
+  auth_prefs.begin("auth", false);
+  sensor_prefs.begin("sensor", false);
+  uint16_t stored_timeout = sensor_prefs.getUShort("i2c_timeout", I2C_TIMEOUT_MS);
+  if (stored_timeout >= MIN_I2C_TIMEOUT_MS && stored_timeout <= MAX_I2C_TIMEOUT_MS)
+  {
+    i2c_timeout = stored_timeout;
+  }
+  else
+  {
+    logLine("Stored I2C timeout %d ms is out of range, using %d ms", stored_timeout, I2C_TIMEOUT_MS);
+  }
+  oled_mode = sensor_prefs.getString("oled", "auto");
+  startHotspot();
+
+  // register everything from the routes table, everything that changes something needs the admin password if one is set
+  for (const Route &route : routes)
+  {
+    WebServer::THandlerFunction handler = route.handler;
+    WebServer::THandlerFunction upload = route.upload;
+    if (route.method == HTTP_POST)
+    {
+      handler = [route]()
+      {
+        if (!isAuthorized())
+        {
+          server.requestAuthentication(BASIC_AUTH, "Td-Free");
+          return;
+        }
+        route.handler();
+      };
+      if (route.upload != nullptr)
+      {
+        upload = [route]()
+        {
+          if (uploadStarting())
+          {
+            upload_authorized = isAuthorized();
+          }
+          if (upload_authorized)
+          {
+            route.upload();
+          }
+        };
+      }
+    }
+
+    if (upload)
+    {
+      server.on(route.path, route.method, handler, upload);
+    }
+    else
+    {
+      server.on(route.path, route.method, handler);
+    }
+  }
 
   // serve portal page
 
   server.onNotFound(handleNotFound);
+  const char *collected_headers[] = {"Accept", "Content-Type"};
+  server.collectHeaders(collected_headers, 2);
   server.begin();
+  webSocket.begin();
 
 
   if (!veml.begin()) {
-    Serial.println("Sensor not found");
-    while (1);
+    logLine("Sensor not found");
+    // keep serving so the log can still be read
+    while (1) {
+      dnsServer.processNextRequest();
+      server.handleClient();
+      delay(10);
+    }
   }
+  Wire.setTimeOut(i2c_timeout);
+  logLine("I2C timeout: %d ms", i2c_timeout);
+  initDisplay();
+  // hotspot, web server and sensor are up
+  markFirmwareValid();
 
-  float max_reading = 0;
-  int sample_count = 10;
-  for (int i = 0; i < sample_count; i++) {
-    max_reading += veml.readLux();
+  for (int attempt = 1; attempt <= CALIBRATION_ATTEMPTS; attempt++) {
+    autoGain();
+    Baseline baseline = takeBaselineReading();
+    baseline_reading = baseline.lux;
+    baseline_config = baseline.config;
+    if (isValidBaseline(baseline_reading)) {
+      break;
+    }
+    logLine("Calibration attempt %d/%d looks invalid, re-enabling sensor", attempt, CALIBRATION_ATTEMPTS);
+    // power cycle the sensor before trying again
+    veml.enable(false);
+    delay(100);
+    veml.enable(true);
     delay(200);
   }
-  baseline_reading = max_reading / sample_count;
+  if (!isValidBaseline(baseline_reading)) {
+    logLine("WARNING: Calibration failed, Td values will be wrong!");
+    baseline_reading = 0;
+  }
+  logLine("Baseline: %.2f lux", baseline_reading);
   // Now we're ready to get readings!
 }
 
 void loop(void)
 {
-  Serial.println("LOOP");
+  if (millis() - last_sample >= min_sample_interval) {
+    Serial.println("LOOP");
+    last_sample = millis();
+
+    // on a failed read the last good values stay, sensor_failures > 0 marks them as stale
+    if (checkSensor()) {
+      // one read, so the lux is always computed from the ALS count that gets reported with it
+      uint16_t raw_als = veml.readALS(true);
+      if (raw_als == 0xFFFF) {
+        sensorFailed("Sensor read failed");
+      } else {
+        if (sensor_failures > 0) {
+          logLine("Sensor is back after %d failed reads", sensor_failures);
+          sensor_failures = 0;
+        }
+        current_als = raw_als;
+        current_lux = alsToLux(current_als);
 
-  float current_lux = veml.readLux();
-  
-  // Calculate the transmission percentage
-  if (baseline_reading != 0) {
-    final_td = (current_lux / baseline_reading) * 100.0;
-  } else {
-    final_td = 0; // Avoid division by zero
+        // Calculate the transmission percentage
+        if (baseline_reading != 0) {
+          final_td = (current_lux / baseline_reading) * 100.0;
+          countMeasurement();
+        } else {
+          final_td = 0; // Avoid division by zero
+        }
+      }
+    }
+    broadcastReading();
   }
 
+  updateDisplay();
+  webSocket.loop();
   dnsServer.processNextRequest();
   server.handleClient();
-  delay(100);
+  delay(10);
 }
\ No newline at end of file